@@ -11,12 +11,11 @@ extern crate irclap;
 extern crate clap;
 
 extern crate irc;
-extern crate tokio_core;
+extern crate tokio;
 
 use irc::client::prelude::*;
-use tokio_core::reactor::Core;
 
-fn echo_matches<'a>(matches: clap::ArgMatches<'a>, responder: Box<irclap::IrclapResponseStream + 'a>) {
+fn echo_matches<'a>(matches: clap::ArgMatches<'a>, responder: Box<dyn irclap::IrclapResponseStream + 'a>) {
     let echo:Vec<&str> = matches.values_of("ECHO").map(|v| v.collect()).unwrap_or(vec![]);
     let message = if echo.len() > 0 {
         echo.join(" ")
@@ -26,16 +25,20 @@ fn echo_matches<'a>(matches: clap::ArgMatches<'a>, responder: Box<irclap::Irclap
     responder.send_message(&message);
 }
 
-fn main() {
+#[tokio::main(basic_scheduler)]
+async fn main() {
     let clap_yaml = load_yaml!("echo-args.yml");
     let app = clap::App::from_yaml(clap_yaml);
 
     let irc_conf = Config::load("examples/echo-config.toml").unwrap();
 
-    let mut core = Core::new().unwrap();
-
     let cm = irclap::IrclapSimpleContextMapping::none();
-    let irclap = irclap::new_irclap_future(core.handle(), &irc_conf, app, cm, echo_matches);
+    let trigger = irclap::TriggerConfig::nick_only();
+
+    let local = tokio::task::LocalSet::new();
+    let result = local.run_until(irclap::new_irclap_future(
+        irc_conf, trigger, app, cm, echo_matches, irclap::AllowAll, irclap::NoEventHandler
+    )).await;
 
-    println!("{:?}", core.run(irclap))
+    println!("{:?}", result)
 }
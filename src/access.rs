@@ -0,0 +1,159 @@
+use regex::Regex;
+
+use crate::{AccessDecision, IrclapAccessControl, IrclapContext};
+
+/**
+ * Authorizes commands by matching the sender's `nick!user@host` against
+ * allow/deny regular expressions, with optional per-subcommand gating so
+ * e.g. `admin`-namespaced subcommands require a privileged mask while
+ * everything else stays open.
+ *
+ * `deny` is checked first and always wins, then `allow` (if non-empty,
+ * a hostmask must match one of them), and finally, if the matched
+ * subcommand was registered via
+ * [privileged_subcommand][HostmaskAccessControl::privileged_subcommand],
+ * the hostmask must also match one of `privileged`.
+ *
+ * A context with no hostmask (such as a CLI call) matches none of the
+ * patterns.
+ */
+pub struct HostmaskAccessControl {
+    allow: Vec<Regex>,
+    deny: Vec<Regex>,
+    privileged: Vec<Regex>,
+    privileged_subcommands: Vec<String>,
+    refusal: String,
+}
+
+impl HostmaskAccessControl {
+    /**
+     * No restrictions: everyone is allowed, and nothing requires a
+     * privileged mask. Customize with the other builder methods.
+     */
+    pub fn open() -> HostmaskAccessControl {
+        HostmaskAccessControl {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            privileged: Vec::new(),
+            privileged_subcommands: Vec::new(),
+            refusal: "You are not authorized to run that command.".to_owned(),
+        }
+    }
+
+    /**
+     * Restricts access to hostmasks matching `pattern`. May be called
+     * more than once; a hostmask need only match one of them. If never
+     * called, everyone is allowed (subject to `deny`).
+     */
+    pub fn allow(mut self, pattern: Regex) -> HostmaskAccessControl {
+        self.allow.push(pattern);
+        self
+    }
+
+    /**
+     * Hostmasks matching `pattern` are always denied, regardless of
+     * `allow`.
+     */
+    pub fn deny(mut self, pattern: Regex) -> HostmaskAccessControl {
+        self.deny.push(pattern);
+        self
+    }
+
+    /**
+     * Hostmasks matching `pattern` are considered privileged, and so may
+     * run subcommands registered with
+     * [privileged_subcommand][HostmaskAccessControl::privileged_subcommand].
+     */
+    pub fn privileged(mut self, pattern: Regex) -> HostmaskAccessControl {
+        self.privileged.push(pattern);
+        self
+    }
+
+    /**
+     * Requires a privileged hostmask to run the named top-level
+     * subcommand (e.g. `"admin"`). Subcommands not named here stay open
+     * to anyone passing `allow`/`deny`.
+     */
+    pub fn privileged_subcommand(mut self, name: &str) -> HostmaskAccessControl {
+        self.privileged_subcommands.push(name.to_owned());
+        self
+    }
+
+    /**
+     * Overrides the line sent back on denial.
+     */
+    pub fn refusal_message(mut self, message: &str) -> HostmaskAccessControl {
+        self.refusal = message.to_owned();
+        self
+    }
+}
+
+impl IrclapAccessControl for HostmaskAccessControl {
+    fn authorize(&self, context: &IrclapContext, matches: &::clap::ArgMatches) -> AccessDecision {
+        let hostmask = context.hostmask();
+        let matches_any = |patterns: &[Regex]| match hostmask {
+            Some(ref mask) => patterns.iter().any(|pattern| pattern.is_match(mask)),
+            None => false,
+        };
+
+        if matches_any(&self.deny) {
+            return AccessDecision::Deny;
+        }
+
+        if !self.allow.is_empty() && !matches_any(&self.allow) {
+            return AccessDecision::Deny;
+        }
+
+        let needs_privilege = matches.subcommand_name()
+            .map(|name| self.privileged_subcommands.iter().any(|p| p == name))
+            .unwrap_or(false);
+
+        if needs_privilege && !matches_any(&self.privileged) {
+            return AccessDecision::Deny;
+        }
+
+        AccessDecision::Allow
+    }
+
+    fn refusal_message(&self) -> &str {
+        &self.refusal
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use irc::client::prelude::{Message, Prefix};
+
+    fn msg_from(prefix: &str) -> Message {
+        let mut msg = Message::new(Some(prefix), "PRIVMSG", vec!["#chan"], Some("admin ban someone")).unwrap();
+        msg.prefix = Some(Prefix::new_from_str(prefix));
+        msg
+    }
+
+    #[test]
+    fn denies_masks_not_on_the_allow_list() {
+        let control = HostmaskAccessControl::open().allow(Regex::new(r"^trusted!").unwrap());
+        let matches = ::clap::ArgMatches::new();
+
+        let allowed_msg = msg_from("trusted!user@host");
+        let denied_msg = msg_from("stranger!user@host");
+        let allowed = IrclapContext::Irc(&allowed_msg);
+        let denied = IrclapContext::Irc(&denied_msg);
+
+        assert_eq!(control.authorize(&allowed, &matches), AccessDecision::Allow);
+        assert_eq!(control.authorize(&denied, &matches), AccessDecision::Deny);
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let control = HostmaskAccessControl::open()
+            .allow(Regex::new(r".*").unwrap())
+            .deny(Regex::new(r"^banned!").unwrap());
+        let matches = ::clap::ArgMatches::new();
+
+        let denied_msg = msg_from("banned!user@host");
+        let denied = IrclapContext::Irc(&denied_msg);
+        assert_eq!(control.authorize(&denied, &matches), AccessDecision::Deny);
+    }
+}
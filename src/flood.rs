@@ -0,0 +1,193 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::Duration;
+
+use irc::client::prelude as ircp;
+
+/**
+ * Leaves headroom for the worst-case `:nick!user@host PRIVMSG target :`
+ * prefix a server may prepend before relaying a line back out to other
+ * clients, so lines built to this budget don't get silently truncated.
+ */
+pub const DEFAULT_PAYLOAD_BUDGET: usize = 400;
+
+/**
+ * How many lines the default flood limiter allows through before it
+ * starts queueing, and how often the bucket gets a token back.
+ */
+pub const DEFAULT_FLOOD_CAPACITY: usize = 4;
+pub const DEFAULT_FLOOD_PERIOD_SECS: u64 = 2;
+
+/**
+ * Splits `text` on UTF-8-safe boundaries into chunks no longer than
+ * `budget` bytes each, preferring to break on whitespace so words stay
+ * whole. A single token longer than `budget` is hard-split.
+ *
+ * `text` is also hard-split on any `\r`/`\n` first, regardless of budget,
+ * since a chunk is sent as the trailing parameter of a single IRC line:
+ * an embedded newline would otherwise let the sender smuggle additional
+ * raw lines/commands past the server.
+ */
+pub fn split_for_irc(text: &str, budget: usize) -> Vec<String> {
+    let budget = budget.max(1);
+    let mut chunks = Vec::new();
+
+    for line in text.split(|c| c == '\r' || c == '\n') {
+        if !line.is_empty() {
+            split_line_for_irc(line, budget, &mut chunks);
+        }
+    }
+
+    chunks
+}
+
+fn split_line_for_irc(line: &str, budget: usize, chunks: &mut Vec<String>) {
+    let mut remaining = line;
+
+    while !remaining.is_empty() {
+        if remaining.len() <= budget {
+            chunks.push(remaining.to_owned());
+            break;
+        }
+
+        let split_at = floor_char_boundary(remaining, budget);
+
+        if let Some(ws) = remaining[..split_at].rfind(char::is_whitespace) {
+            let (chunk, rest) = remaining.split_at(ws);
+            chunks.push(chunk.to_owned());
+            remaining = rest.trim_start();
+        } else {
+            let (chunk, rest) = remaining.split_at(split_at);
+            chunks.push(chunk.to_owned());
+            remaining = rest;
+        }
+    }
+}
+
+fn floor_char_boundary(s: &str, idx: usize) -> usize {
+    let mut idx = idx.min(s.len());
+    while idx > 0 && !s.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+struct QueuedLine {
+    target: String,
+    notice: bool,
+    line: String,
+}
+
+/**
+ * A simple token-bucket flood limiter. Up to `capacity` lines may be sent
+ * immediately; once the bucket is drained, further lines are queued and
+ * released in order as the bucket refills.
+ */
+pub(crate) struct FloodLimiter {
+    tokens: usize,
+    capacity: usize,
+    queue: VecDeque<QueuedLine>,
+}
+
+impl FloodLimiter {
+    pub(crate) fn new(capacity: usize) -> FloodLimiter {
+        FloodLimiter {
+            tokens: capacity,
+            capacity: capacity,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /**
+     * Sends `line` to `target` immediately if a token is available and
+     * nothing is already queued ahead of it, otherwise queues it to be
+     * released in order as the bucket refills.
+     */
+    pub(crate) fn send(&mut self, client: &ircp::Client, target: &str, notice: bool, line: String) -> Result<(), ::irc::error::Error> {
+        if self.tokens > 0 && self.queue.is_empty() {
+            self.tokens -= 1;
+            send_line(client, target, notice, &line)
+        } else {
+            self.queue.push_back(QueuedLine { target: target.to_owned(), notice: notice, line: line });
+            Ok(())
+        }
+    }
+
+    /**
+     * Adds a token back to the bucket (capped at `capacity`) and drains
+     * as many queued lines as the refreshed bucket allows.
+     */
+    pub(crate) fn refill(&mut self, client: &ircp::Client) {
+        if self.tokens < self.capacity {
+            self.tokens += 1;
+        }
+
+        while self.tokens > 0 {
+            match self.queue.pop_front() {
+                Some(queued) => {
+                    self.tokens -= 1;
+                    //Best-effort: a dropped connection will fail every send,
+                    //but there's no response target left to report that to.
+                    let _ = send_line(client, &queued.target, queued.notice, &queued.line);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+fn send_line(client: &ircp::Client, target: &str, notice: bool, line: &str) -> Result<(), ::irc::error::Error> {
+    if notice {
+        client.send_notice(target, line)
+    } else {
+        client.send_privmsg(target, line)
+    }
+}
+
+/**
+ * Spawns a task onto the current [tokio::task::LocalSet] that periodically
+ * refills `limiter`'s token bucket, releasing any backlog of queued lines
+ * in the process. `client` and `limiter` are both `Rc`-based, so this must
+ * be called from within a `LocalSet`.
+ */
+pub(crate) fn spawn_refill_timer(client: Rc<ircp::Client>, limiter: Rc<RefCell<FloodLimiter>>, period: Duration) {
+    tokio::task::spawn_local(async move {
+        let mut ticker = tokio::time::interval(period);
+        loop {
+            ticker.tick().await;
+            limiter.borrow_mut().refill(&client);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn splits_on_whitespace_within_budget() {
+        let chunks = split_for_irc("the quick brown fox jumps", 10);
+        assert_eq!(chunks, vec!["the quick", "brown fox", "jumps"]);
+    }
+
+    #[test]
+    fn hard_splits_a_token_longer_than_budget() {
+        let chunks = split_for_irc("supercalifragilisticexpialidocious", 10);
+        assert_eq!(chunks, vec!["supercalif", "ragilistic", "expialidoc", "ious"]);
+    }
+
+    #[test]
+    fn leaves_short_text_untouched() {
+        assert_eq!(split_for_irc("hi there", 400), vec!["hi there"]);
+        assert!(split_for_irc("", 400).is_empty());
+    }
+
+    #[test]
+    fn hard_splits_on_embedded_crlf_even_under_budget() {
+        assert_eq!(split_for_irc("line one\r\nline two", 400), vec!["line one", "line two"]);
+        assert_eq!(split_for_irc("a\nb\rc\r\nd", 400), vec!["a", "b", "c", "d"]);
+        assert_eq!(split_for_irc("PRIVMSG #other :injected\r\nhi", 400),
+                   vec!["PRIVMSG #other :injected", "hi"]);
+    }
+}
@@ -1,46 +1,147 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use irc::client::prelude as ircp;
 use irc::client::prelude::ChannelExt;
-use irc::client::prelude::ClientExt;
 
-use IrclapResponseStream;
+use crate::IrclapResponseStream;
+use crate::TriggerConfig;
+use crate::flood::{self, FloodLimiter};
 
 pub(crate) struct IrcResponseStream<'c> {
-    client: &'c ircp::IrcClient,
+    client: &'c ircp::Client,
     response_target: &'c str,
+    flood: Rc<RefCell<FloodLimiter>>,
 }
 
 impl<'c> IrcResponseStream<'c> {
-    pub(crate) fn new(client: &'c ircp::IrcClient, rt: &'c str) -> IrcResponseStream<'c> {
+    pub(crate) fn new(client: &'c ircp::Client, rt: &'c str, flood: Rc<RefCell<FloodLimiter>>) -> IrcResponseStream<'c> {
         IrcResponseStream {
             client: client,
             response_target: rt,
+            flood: flood,
         }
     }
 }
 
 impl<'c> IrclapResponseStream for IrcResponseStream<'c> {
-    fn send_message(&self, msg: &str) -> Result<(), ::irc::error::IrcError>{
-        if self.response_target.is_channel_name() {
-            self.client.send_notice(self.response_target, msg)
-        } else {
-            self.client.send_privmsg(self.response_target, msg)
+    fn send_message(&self, msg: &str) -> Result<(), ::irc::error::Error>{
+        let notice = self.response_target.is_channel_name();
+        let mut flood = self.flood.borrow_mut();
+
+        for chunk in flood::split_for_irc(msg, flood::DEFAULT_PAYLOAD_BUDGET) {
+            flood.send(self.client, self.response_target, notice, chunk)?;
         }
+
+        Ok(())
     }
 }
 
 fn strip_botname<'m>(botname: &str, msg: &'m str) -> Option<&'m str> {
-    if msg.starts_with(botname) {
-        Some(&msg[botname.len()..].trim_matches(':').trim_matches(',').trim())
+    msg.strip_prefix(botname).map(|rest| rest.trim_matches(':').trim_matches(',').trim())
+}
+
+const CTCP_DELIM: char = '\u{1}';
+
+#[cfg(feature = "ctcp")]
+fn strip_ctcp_wrapper<'m>(m: &'m str) -> Option<&'m str> {
+    if m.len() >= 2 && m.starts_with(CTCP_DELIM) && m.ends_with(CTCP_DELIM) {
+        Some(&m[1..m.len() - 1])
     } else {
         None
     }
 }
 
-pub(crate) fn extract_command<'m>(botname:&str, msg: &'m ircp::Message) -> Option<&'m str> {
+/**
+ * If `m` is a `/me`-style CTCP ACTION (`\x01ACTION ...\x01`), returns the
+ * action text with the wrapper removed so it can still be matched like any
+ * other command.
+ */
+#[cfg(feature = "ctcp")]
+fn strip_ctcp_action<'m>(m: &'m str) -> Option<&'m str> {
+    strip_ctcp_wrapper(m).and_then(|inner| {
+        if inner == "ACTION" {
+            Some("")
+        } else if inner.starts_with("ACTION ") {
+            Some(&inner["ACTION ".len()..])
+        } else {
+            None
+        }
+    })
+}
+
+/**
+ * A standalone CTCP query, i.e. anything wrapped in `\x01...\x01` that
+ * isn't an ACTION. `argument` holds anything following the verb, such as
+ * the token a `PING` query expects echoed back.
+ */
+#[cfg(feature = "ctcp")]
+pub(crate) struct CtcpQuery<'m> {
+    pub(crate) verb: &'m str,
+    pub(crate) argument: &'m str,
+}
+
+/**
+ * Recognizes standalone CTCP queries (`VERSION`, `PING`, `TIME`, `SOURCE`,
+ * etc.) so they can be answered directly instead of falling through to
+ * `extract_command` and being misinterpreted as clap input. Returns `None`
+ * for CTCP ACTION, which `extract_command` already handles.
+ */
+#[cfg(feature = "ctcp")]
+pub(crate) fn extract_ctcp_query<'m>(msg: &'m ircp::Message) -> Option<CtcpQuery<'m>> {
+    match msg.command {
+        ircp::Command::PRIVMSG(_, ref m) => {
+            strip_ctcp_wrapper(m).and_then(|inner| {
+                if inner == "ACTION" || inner.starts_with("ACTION ") {
+                    return None;
+                }
+
+                let mut parts = inner.splitn(2, ' ');
+                let verb = parts.next().unwrap_or("");
+                let argument = parts.next().unwrap_or("");
+                Some(CtcpQuery { verb: verb, argument: argument })
+            })
+        }
+        _ => None
+    }
+}
+
+/**
+ * Extracts the command text from a message, if any trigger configured in
+ * `config` matches: nick-addressing (`botname`), or one of the configured
+ * sigil prefixes. In private messages, a message matching no trigger is
+ * still treated as a command when `config.implicit_in_private` is set,
+ * preserving irclap's original fallback behavior.
+ */
+pub(crate) fn extract_command<'m>(config: &TriggerConfig, botname: &str, msg: &'m ircp::Message) -> Option<&'m str> {
     let is_channel = msg.response_target().map(|rt| rt.is_channel_name()).unwrap_or(false);
     match msg.command {
-        ircp::Command::PRIVMSG(_, ref m) if is_channel => strip_botname(botname, m),
-        ircp::Command::PRIVMSG(_, ref m) => strip_botname(botname, m).or(Some(m)),
+        ircp::Command::PRIVMSG(_, ref m) => {
+            #[cfg(feature = "ctcp")]
+            let text: &str = strip_ctcp_action(m).unwrap_or(m);
+            #[cfg(not(feature = "ctcp"))]
+            let text: &str = m;
+
+            let prefix_required = is_channel && config.require_prefix_in_channels;
+
+            if !prefix_required && config.nick_addressing {
+                if let Some(stripped) = strip_botname(botname, text) {
+                    return Some(stripped);
+                }
+            }
+
+            for prefix in &config.prefixes {
+                if text.starts_with(prefix.as_str()) {
+                    return Some(text[prefix.len()..].trim());
+                }
+            }
+
+            if !is_channel && config.implicit_in_private {
+                Some(text)
+            } else {
+                None
+            }
+        }
         _ => None
     }
 }
@@ -52,11 +153,52 @@ mod test {
 
     #[test]
     fn can_strip_botname_from_msg() {
+        let config = TriggerConfig::nick_only();
+
+        let msg = Message::new(Some("usr"), "PRIVMSG", vec!["#chan"], Some("bot: Hi")).unwrap();
+        assert_eq!(extract_command(&config, "bot", &msg), Some("Hi"));
+
+        let msg = Message::new(Some("usr"), "PRIVMSG", vec!["usr"], Some("bot: Hi")).unwrap();
+        assert_eq!(extract_command(&config, "bot", &msg), Some("Hi"));
+    }
+
+    #[test]
+    fn can_trigger_on_a_configured_prefix() {
+        let config = TriggerConfig::nick_only().with_prefix("!");
+
+        let msg = Message::new(Some("usr"), "PRIVMSG", vec!["#chan"], Some("!weather London")).unwrap();
+        assert_eq!(extract_command(&config, "bot", &msg), Some("weather London"));
+    }
+
+    #[test]
+    fn requiring_a_prefix_in_channels_rejects_bare_nick_address() {
+        let config = TriggerConfig::nick_only().require_prefix_in_channels(true);
+
         let msg = Message::new(Some("usr"), "PRIVMSG", vec!["#chan"], Some("bot: Hi")).unwrap();
-        assert_eq!(extract_command("bot", &msg), Some("Hi"));
+        assert_eq!(extract_command(&config, "bot", &msg), None);
 
         let msg = Message::new(Some("usr"), "PRIVMSG", vec!["usr"], Some("bot: Hi")).unwrap();
-        assert_eq!(extract_command("bot", &msg), Some("Hi"));
+        assert_eq!(extract_command(&config, "bot", &msg), Some("Hi"));
+    }
+
+    #[cfg(feature = "ctcp")]
+    #[test]
+    fn strips_ctcp_action_wrapper_from_msg() {
+        let config = TriggerConfig::nick_only();
+        let msg = Message::new(Some("usr"), "PRIVMSG", vec!["#chan"], Some("\u{1}ACTION bot waves\u{1}")).unwrap();
+        assert_eq!(extract_command(&config, "bot", &msg), Some("waves"));
+    }
+
+    #[cfg(feature = "ctcp")]
+    #[test]
+    fn recognizes_standalone_ctcp_queries() {
+        let msg = Message::new(Some("usr"), "PRIVMSG", vec!["bot"], Some("\u{1}PING 12345\u{1}")).unwrap();
+        let query = extract_ctcp_query(&msg).unwrap();
+        assert_eq!(query.verb, "PING");
+        assert_eq!(query.argument, "12345");
+
+        let action = Message::new(Some("usr"), "PRIVMSG", vec!["bot"], Some("\u{1}ACTION waves\u{1}")).unwrap();
+        assert!(extract_ctcp_query(&action).is_none());
     }
 
 }
@@ -3,28 +3,46 @@
   but not vetted by others, so any comments are very welcome.
 
   The core function of the library is provided through [new_irclap_future],
-  which links together all necessary trait impls and config as a single [Future].
-  That Future can then be driven on a tokio reactor, and you've got yourself an IRC
-  bot.
+  an `async fn` that connects, identifies, and processes messages until the
+  connection ends. Await it from within a [tokio::task::LocalSet], since the
+  flood limiter it spawns is built on `Rc`/`RefCell` rather than anything
+  `Send`.
+
+  Enable the `ctcp` feature to get `/me` (CTCP ACTION) support in commands, plus
+  automatic replies to `VERSION`/`PING`/`TIME`/`SOURCE` queries via
+  [new_irclap_future_with_ctcp] and [IrclapCtcpResponder].
+
+  The same `App`, mapper, and processor can also be driven as a plain
+  command-line tool via [run_cli], without needing an IRC connection at all.
+
+  Besides commands, an [IrclapEventHandler] can react to joins, parts,
+  kicks, nick changes, and topic changes on their own, without going
+  through clap at all.
   */
 
-#![feature(conservative_impl_trait)]
 extern crate irc;
 extern crate clap;
 extern crate futures;
-extern crate tokio_core;
+extern crate tokio;
+extern crate regex;
 
 use irc::client::prelude as ircp;
-use irc::client::prelude::{Client, ClientExt};
-use irc::client::{PackedIrcClient};
-
-use tokio_core::reactor::Handle;
+use irc::client::prelude::ChannelExt;
+use irc::client::ClientStream;
 
-use futures::{Future, Stream};
+use futures::stream::StreamExt;
 
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::env;
+use std::rc::Rc;
+use std::time::Duration;
 
 mod irc_util;
+mod flood;
+mod access;
+
+pub use access::HostmaskAccessControl;
 
 /**
  * Sends out messages to whatever channel, nickname, or other
@@ -38,14 +56,94 @@ pub trait IrclapResponseStream {
     /**
      * Sends a single line to the appropriate response target.
      */
-    fn send_message(&self, msg: &str) -> Result<(), irc::error::IrcError>;
+    fn send_message(&self, msg: &str) -> Result<(), irc::error::Error>;
 }
 
 /**
- * Provides a mapping between the context provided by an IRC message
- * and the actual arguments needed to run a clap application.
- * Also allows for special transformations to any arguments provided
- * in the message.
+ * Where a command came from: either a real IRC message, or values
+ * supplied directly by a CLI caller via [run_cli]. Abstracts over the two
+ * so [IrclapContextMapper] and [IrclapAccessControl] can be driven by
+ * either without requiring a real `ircp::Message` to exist.
+ */
+pub enum IrclapContext<'a> {
+    Irc(&'a ircp::Message),
+    Cli {
+        channel: Option<&'a str>,
+        username: Option<&'a str>,
+    },
+}
+
+impl<'a> IrclapContext<'a> {
+    /**
+     * The channel or nick a reply should be sent to. Always `None` for
+     * [Cli][IrclapContext::Cli], since there's nowhere to reply to.
+     */
+    pub fn response_target(&self) -> Option<&'a str> {
+        match *self {
+            IrclapContext::Irc(msg) => msg.response_target(),
+            IrclapContext::Cli { .. } => None,
+        }
+    }
+
+    /**
+     * The nick of whoever sent the command, or the CLI-supplied username.
+     */
+    pub fn source_nickname(&self) -> Option<&'a str> {
+        match *self {
+            IrclapContext::Irc(msg) => msg.source_nickname(),
+            IrclapContext::Cli { username, .. } => username,
+        }
+    }
+
+    /**
+     * The channel the command was sent in/for, if any.
+     */
+    pub fn channel(&self) -> Option<&'a str> {
+        match *self {
+            IrclapContext::Irc(msg) => match msg.response_target() {
+                Some(rt) if rt.is_channel_name() => Some(rt),
+                _ => None,
+            },
+            IrclapContext::Cli { channel, .. } => channel,
+        }
+    }
+
+    /**
+     * The sender's `nick!user@host`, if this came from IRC. CLI calls have
+     * no hostmask, so hostmask-based [IrclapAccessControl] impls should
+     * treat `None` as "no one to authorize" rather than "everyone".
+     *
+     * Owned, rather than borrowed, since `ircp::Message::prefix` is a
+     * [Prefix][ircp::Prefix] (only `Display`, not a `String`) as of irc 0.14.
+     */
+    pub fn hostmask(&self) -> Option<String> {
+        match *self {
+            IrclapContext::Irc(msg) => msg.prefix.as_ref().map(|p| p.to_string()),
+            IrclapContext::Cli { .. } => None,
+        }
+    }
+
+    /**
+     * Looks up an IRCv3 message tag (e.g. `"account"`, `"msgid"`) by key.
+     * Always `None` outside of IRC, since CLI calls have no tags.
+     */
+    pub fn tag(&self, key: &str) -> Option<&'a str> {
+        match *self {
+            IrclapContext::Irc(msg) => msg.tags.as_ref().and_then(|tags| {
+                tags.iter()
+                    .find(|tag| tag.0 == key)
+                    .and_then(|tag| tag.1.as_deref())
+            }),
+            IrclapContext::Cli { .. } => None,
+        }
+    }
+}
+
+/**
+ * Provides a mapping between the context a command came from (an IRC
+ * message, or values supplied over the CLI) and the actual arguments
+ * needed to run a clap application. Also allows for special
+ * transformations to any arguments provided in the message.
  */
 pub trait IrclapContextMapper {
     /**
@@ -57,7 +155,7 @@ pub trait IrclapContextMapper {
      *
      * Must return the full list of arguments to be parsed by `clap::App`.
      */
-    fn prepare_command_args<'a>(&'a self, args: Vec<&'a str>, msg: &'a ircp::Message) -> Vec<Cow<'a, str>>;
+    fn prepare_command_args<'a>(&'a self, args: Vec<&'a str>, context: &IrclapContext<'a>) -> Vec<Cow<'a, str>>;
 }
 
 /**
@@ -88,14 +186,61 @@ pub trait IrclapCommandProcessor {
         where RS: IrclapResponseStream + 'a;
 }
 
-impl<F> IrclapCommandProcessor for F where F: for<'af> Fn(clap::ArgMatches<'af>, Box<IrclapResponseStream + 'af>) {
+impl<F> IrclapCommandProcessor for F where F: for<'af> Fn(clap::ArgMatches<'af>, Box<dyn IrclapResponseStream + 'af>) {
     fn process_matches<'a, RS>(&self, matches: clap::ArgMatches<'a>, resp: RS)
         where RS: IrclapResponseStream + 'a{
-        let rstream = Box::new(resp) as Box<IrclapResponseStream + 'a>;
+        let rstream = Box::new(resp) as Box<dyn IrclapResponseStream + 'a>;
         (self)(matches, rstream)
     }
 }
 
+/**
+ * The result of [IrclapAccessControl::authorize].
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub enum AccessDecision {
+    Allow,
+    Deny,
+}
+
+/**
+ * Gates whether a successfully-parsed command is actually allowed to run,
+ * based on the sender and/or the parsed arguments. Invoked after
+ * `get_matches_from_safe` succeeds, but before
+ * [process_matches][IrclapCommandProcessor::process_matches].
+ *
+ * On [Deny][AccessDecision::Deny], [refusal_message][IrclapAccessControl::refusal_message]
+ * is sent back through the [IrclapResponseStream] instead of running the processor.
+ */
+pub trait IrclapAccessControl {
+    /**
+     * Decides whether `matches` is allowed to run, given the context it
+     * was parsed from (so the sender's hostmask is available for IRC
+     * commands via [IrclapContext::hostmask]).
+     */
+    fn authorize(&self, context: &IrclapContext, matches: &clap::ArgMatches) -> AccessDecision;
+
+    /**
+     * The line sent back when `authorize` returns `Deny`.
+     */
+    fn refusal_message(&self) -> &str {
+        "You are not authorized to run that command."
+    }
+}
+
+/**
+ * Allows everything. Pass this as the access control to
+ * [new_irclap_future] when no restriction is needed; see
+ * [HostmaskAccessControl] for a built-in way to actually restrict access.
+ */
+pub struct AllowAll;
+
+impl IrclapAccessControl for AllowAll {
+    fn authorize(&self, _context: &IrclapContext, _matches: &clap::ArgMatches) -> AccessDecision {
+        AccessDecision::Allow
+    }
+}
+
 /**
  * Supports extracting common context values from IRC messages
  * into args for processing.
@@ -108,6 +253,7 @@ impl<F> IrclapCommandProcessor for F where F: for<'af> Fn(clap::ArgMatches<'af>,
 pub struct IrclapSimpleContextMapping {
     pub channel: Option<String>,
     pub username: Option<String>,
+    pub account_tag_arg: Option<String>,
 }
 
 impl IrclapSimpleContextMapping {
@@ -120,9 +266,24 @@ impl IrclapSimpleContextMapping {
         IrclapSimpleContextMapping{
             channel: None,
             username: None,
+            account_tag_arg: None,
         }
     }
 
+    /**
+     * Maps the IRCv3 `account` message tag (the sender's
+     * SASL-authenticated account name) to `arg`, e.g. `"--account"`.
+     * Unlike the raw nick, this can't be spoofed by an unauthenticated
+     * client and survives nick changes, which makes it a much more
+     * trustworthy identity to key access control decisions on. Only
+     * populated for servers that send the `account` tag; absent
+     * otherwise.
+     */
+    pub fn account_tag_arg(mut self, arg: String) -> IrclapSimpleContextMapping {
+        self.account_tag_arg = Some(arg);
+        self
+    }
+
     /**
      * Maps the username of the message to an argument. Does not map the
      * channel at all.
@@ -133,16 +294,17 @@ impl IrclapSimpleContextMapping {
      * # extern crate irc;
      * # extern crate irclap;
      * # use irc::client::prelude::Message;
-     * # use irclap::{IrclapContextMapper, IrclapSimpleContextMapping};
+     * # use irclap::{IrclapContext, IrclapContextMapper, IrclapSimpleContextMapping};
      * //Context, along with a message "arg1 arg2" from 'someuser'
      * let context_mapping = IrclapSimpleContextMapping::user_only("--profile-name".to_owned());
      * let message = Message::new(Some("someuser"), "PRIVMSG", vec!["mybot"], Some("arg1 arg2")).unwrap();
+     * let context = IrclapContext::Irc(&message);
      *
      * //Usually irclap extracts this from the message for us, but we'll hardcode it here
      * let message_args = vec!["arg1", "arg2"];
      *
      * // Now we proces the message and we get username passed as an argument.
-     * let mapped = context_mapping.prepare_command_args(message_args, &message);
+     * let mapped = context_mapping.prepare_command_args(message_args, &context);
      * assert_eq!(vec!["arg1", "arg2", "--profile-name", "someuser"], mapped);
      * ```
      */
@@ -150,62 +312,334 @@ impl IrclapSimpleContextMapping {
         IrclapSimpleContextMapping {
             channel: None,
             username: Some(username),
+            account_tag_arg: None,
         }
     }
 }
 
+/**
+ * Configures how a message must be addressed to the bot before it's
+ * treated as a command at all.
+ *
+ * Two kinds of trigger are supported: nick-addressing (`botname: command`
+ * or `botname, command`), and any number of sigil `prefixes` (`!command`,
+ * `.seen`). Either, both, or neither can be enabled.
+ *
+ * By default, private messages that match no trigger are still treated
+ * as a command, preserving irclap's original fallback behavior; set
+ * `implicit_in_private` to `false` to require an explicit trigger there
+ * too. `require_prefix_in_channels` additionally requires one of
+ * `prefixes` in channels even when nick-addressing is otherwise enabled,
+ * while leaving private messages unaffected.
+ */
+pub struct TriggerConfig {
+    pub prefixes: Vec<String>,
+    pub nick_addressing: bool,
+    pub require_prefix_in_channels: bool,
+    pub implicit_in_private: bool,
+}
+
+impl TriggerConfig {
+    /**
+     * Nick-addressing only, with the legacy implicit-private-message
+     * fallback enabled. This matches irclap's original, and only,
+     * invocation style, so it's a reasonable starting point to customize
+     * with [with_prefix][TriggerConfig::with_prefix] and friends.
+     */
+    pub fn nick_only() -> TriggerConfig {
+        TriggerConfig {
+            prefixes: Vec::new(),
+            nick_addressing: true,
+            require_prefix_in_channels: false,
+            implicit_in_private: true,
+        }
+    }
+
+    /**
+     * Adds `prefix` (e.g. `"!"`) as an accepted trigger.
+     */
+    pub fn with_prefix(mut self, prefix: &str) -> TriggerConfig {
+        self.prefixes.push(prefix.to_owned());
+        self
+    }
+
+    /**
+     * Requires one of `prefixes` to trigger a command in channels, even if
+     * `nick_addressing` is enabled. Private messages are unaffected.
+     */
+    pub fn require_prefix_in_channels(mut self, required: bool) -> TriggerConfig {
+        self.require_prefix_in_channels = required;
+        self
+    }
+
+    /**
+     * Controls whether a private message matching no trigger is still
+     * treated as a command, as irclap has always done.
+     */
+    pub fn implicit_in_private(mut self, implicit: bool) -> TriggerConfig {
+        self.implicit_in_private = implicit;
+        self
+    }
+}
+
 fn arg_tuple_opt<'a>(arg: &'a Option<String>, value: Option<&'a str>) -> Option<(&'a str, &'a str)> {
     arg.as_ref().map(String::as_str).and_then(|a| value.map(|v| (a, v)))
 }
 
 fn push_arg_tuple<'a>(args: &mut Vec<&'a str>, arg: &'a Option<String>, value: Option<&'a str>) {
-    for (a, v) in arg_tuple_opt(arg, value) {
+    if let Some((a, v)) = arg_tuple_opt(arg, value) {
         args.push(a);
         args.push(v);
     }
 }
 
 impl IrclapContextMapper for IrclapSimpleContextMapping {
-    fn prepare_command_args<'a>(&'a self, mut args: Vec<&'a str>, msg: &'a ircp::Message) -> Vec<Cow<'a, str>> {
-        push_arg_tuple(&mut args, &self.channel, msg.response_target());
-        push_arg_tuple(&mut args, &self.username, msg.source_nickname());
+    fn prepare_command_args<'a>(&'a self, mut args: Vec<&'a str>, context: &IrclapContext<'a>) -> Vec<Cow<'a, str>> {
+        push_arg_tuple(&mut args, &self.channel, context.response_target().or_else(|| context.channel()));
+        push_arg_tuple(&mut args, &self.username, context.source_nickname());
+        push_arg_tuple(&mut args, &self.account_tag_arg, context.tag("account"));
         args.into_iter().map(Cow::from).collect()
     }
 }
 
-struct IrclapProcessor<CM, CP> {
+/**
+ * Answers CTCP queries (`VERSION`, `PING`, `TIME`, `SOURCE`) so they don't
+ * fall through to the clap command pipeline and get reported back as
+ * argument errors. Only available with the `ctcp` feature.
+ *
+ * All methods have sensible defaults; override `respond_to` (or just
+ * `version`/`source`) to customize replies.
+ */
+#[cfg(feature = "ctcp")]
+pub trait IrclapCtcpResponder {
+    /**
+     * The string returned for a `VERSION` query.
+     */
+    fn version(&self) -> String {
+        format!("irclap {}", env!("CARGO_PKG_VERSION"))
+    }
+
+    /**
+     * The string returned for a `SOURCE` query.
+     */
+    fn source(&self) -> String {
+        "https://github.com/brandonson/irclap".to_owned()
+    }
+
+    /**
+     * Builds the reply payload for a CTCP query, given its `verb` (e.g.
+     * `"VERSION"`) and anything following it (e.g. the token a `PING`
+     * expects echoed back). Returns `None` for unrecognized verbs, in
+     * which case the query is silently ignored.
+     *
+     * The default implementation handles `VERSION`, `PING`, `TIME`, and
+     * `SOURCE`. `TIME` reports seconds since the Unix epoch, since this
+     * crate doesn't pull in a date-formatting dependency; override this
+     * method if you want a human-readable reply.
+     */
+    fn respond_to(&self, verb: &str, argument: &str) -> Option<String> {
+        match verb {
+            "VERSION" => Some(self.version()),
+            "PING" => Some(argument.to_owned()),
+            "TIME" => {
+                use std::time::{SystemTime, UNIX_EPOCH};
+                let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                Some(format!("{}", secs))
+            }
+            "SOURCE" => Some(self.source()),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * The default [IrclapCtcpResponder], using irclap's own name, crate
+ * version, and repository URL.
+ */
+#[cfg(feature = "ctcp")]
+pub struct DefaultCtcpResponder;
+
+#[cfg(feature = "ctcp")]
+impl IrclapCtcpResponder for DefaultCtcpResponder {}
+
+/**
+ * Reacts to non-command IRC events: users joining, parting, or being
+ * kicked from a channel, changing nick, or a channel's topic changing.
+ * Every method has a do-nothing default, so implementations only need to
+ * override the events they actually care about.
+ */
+pub trait IrclapEventHandler {
+    /** Called when `nick` joins `channel`. */
+    fn on_join<RS: IrclapResponseStream>(&self, channel: &str, nick: &str, resp: RS) {
+        let _ = (channel, nick, resp);
+    }
+
+    /** Called when `nick` parts `channel`, with an optional part message. */
+    fn on_part<RS: IrclapResponseStream>(&self, channel: &str, nick: &str, reason: Option<&str>, resp: RS) {
+        let _ = (channel, nick, reason, resp);
+    }
+
+    /**
+     * Called when `kicked` is kicked from `channel` by `by`. Not called
+     * when the bot itself was kicked; see
+     * [on_self_kicked][IrclapEventHandler::on_self_kicked] for that case.
+     */
+    fn on_kick<RS: IrclapResponseStream>(&self, channel: &str, kicked: &str, by: &str, reason: Option<&str>, resp: RS) {
+        let _ = (channel, kicked, by, reason, resp);
+    }
+
+    /**
+     * Called when the bot itself is kicked from `channel` by `by`. `resp`
+     * still targets `channel`, but the bot is no longer a member, so
+     * sending to it will fail.
+     */
+    fn on_self_kicked<RS: IrclapResponseStream>(&self, channel: &str, by: &str, reason: Option<&str>, resp: RS) {
+        let _ = (channel, by, reason, resp);
+    }
+
+    /** Called when `old_nick` changes their nick to `new_nick`. */
+    fn on_nick_change(&self, old_nick: &str, new_nick: &str) {
+        let _ = (old_nick, new_nick);
+    }
+
+    /** Called when `channel`'s topic changes to `topic` (`None` if cleared). */
+    fn on_topic_change<RS: IrclapResponseStream>(&self, channel: &str, topic: Option<&str>, resp: RS) {
+        let _ = (channel, topic, resp);
+    }
+}
+
+/**
+ * An [IrclapEventHandler] that ignores every event. Pass this if you
+ * don't need to react to joins, parts, kicks, nick changes, or topic
+ * changes.
+ */
+pub struct NoEventHandler;
+
+impl IrclapEventHandler for NoEventHandler {}
+
+fn is_event_command(command: &ircp::Command) -> bool {
+    match *command {
+        ircp::Command::JOIN(..) |
+        ircp::Command::PART(..) |
+        ircp::Command::KICK(..) |
+        ircp::Command::NICK(..) |
+        ircp::Command::TOPIC(..) => true,
+        _ => false,
+    }
+}
+
+struct IrclapProcessor<CM, CP, AC, EH> {
+    trigger: TriggerConfig,
     mapper: CM,
     processor: CP,
+    access: AC,
+    events: EH,
+    #[cfg(feature = "ctcp")]
+    ctcp: Option<Box<dyn IrclapCtcpResponder>>,
 }
 
-impl<CM, CP> IrclapProcessor<CM, CP> {
-    fn new(mapper: CM, processor: CP) -> IrclapProcessor<CM, CP> {
+impl<CM, CP, AC, EH> IrclapProcessor<CM, CP, AC, EH> {
+    fn new(trigger: TriggerConfig, mapper: CM, processor: CP, access: AC, events: EH) -> IrclapProcessor<CM, CP, AC, EH> {
         IrclapProcessor {
+            trigger: trigger,
             mapper: mapper,
             processor: processor,
+            access: access,
+            events: events,
+            #[cfg(feature = "ctcp")]
+            ctcp: None,
         }
     }
+
+    #[cfg(feature = "ctcp")]
+    fn with_ctcp_responder(mut self, responder: Box<dyn IrclapCtcpResponder>) -> IrclapProcessor<CM, CP, AC, EH> {
+        self.ctcp = Some(responder);
+        self
+    }
 }
 
-fn process_single_message<'a, CM, CP>(
+fn process_single_message<'a, CM, CP, AC, EH>(
     app: clap::App<'a, 'a>,
-    context: &IrclapProcessor<CM, CP>,
-    client: &ircp::IrcClient,
+    context: &IrclapProcessor<CM, CP, AC, EH>,
+    client: &ircp::Client,
+    flood: Rc<RefCell<flood::FloodLimiter>>,
     msg: ircp::Message)
     where CM: IrclapContextMapper,
-          CP: IrclapCommandProcessor {
-    if let Some(command) = irc_util::extract_command(client.current_nickname(), &msg) {
+          CP: IrclapCommandProcessor,
+          AC: IrclapAccessControl,
+          EH: IrclapEventHandler {
+    match msg.command {
+        ircp::Command::JOIN(ref chan, _, _) => {
+            if let Some(nick) = msg.source_nickname() {
+                let resp = irc_util::IrcResponseStream::new(client, chan, flood);
+                context.events.on_join(chan, nick, resp);
+            }
+            return;
+        }
+        ircp::Command::PART(ref chan, ref reason) => {
+            if let Some(nick) = msg.source_nickname() {
+                let resp = irc_util::IrcResponseStream::new(client, chan, flood);
+                context.events.on_part(chan, nick, reason.as_ref().map(String::as_str), resp);
+            }
+            return;
+        }
+        ircp::Command::KICK(ref chan, ref kicked, ref reason) => {
+            if let Some(by) = msg.source_nickname() {
+                let resp = irc_util::IrcResponseStream::new(client, chan, flood);
+                if kicked.as_str() == client.current_nickname() {
+                    context.events.on_self_kicked(chan, by, reason.as_ref().map(String::as_str), resp);
+                } else {
+                    context.events.on_kick(chan, kicked, by, reason.as_ref().map(String::as_str), resp);
+                }
+            }
+            return;
+        }
+        ircp::Command::NICK(ref new_nick) => {
+            if let Some(old_nick) = msg.source_nickname() {
+                context.events.on_nick_change(old_nick, new_nick);
+            }
+            return;
+        }
+        ircp::Command::TOPIC(ref chan, ref topic) => {
+            let resp = irc_util::IrcResponseStream::new(client, chan, flood);
+            context.events.on_topic_change(chan, topic.as_ref().map(String::as_str), resp);
+            return;
+        }
+        _ => {}
+    }
+
+    #[cfg(feature = "ctcp")]
+    {
+        if let Some(query) = irc_util::extract_ctcp_query(&msg) {
+            if let (Some(responder), Some(nick)) = (context.ctcp.as_ref(), msg.source_nickname()) {
+                if let Some(reply) = responder.respond_to(query.verb, query.argument) {
+                    let _ = client.send_notice(nick, &format!("\u{1}{} {}\u{1}", query.verb, reply));
+                }
+            }
+            return;
+        }
+    }
+
+    if let Some(command) = irc_util::extract_command(&context.trigger, client.current_nickname(), &msg) {
         let args:Vec<&str> = command.split_whitespace().collect();
-        let args = context.mapper.prepare_command_args(args, &msg);
+        let msg_context = IrclapContext::Irc(&msg);
+        let args = context.mapper.prepare_command_args(args, &msg_context);
 
         /* We don't process messages without response targets,
          * so it's ok to unwrap here.
          * (see process_message_streams for filtering)
          */
-        let out_stream = irc_util::IrcResponseStream::new(&client, msg.response_target().unwrap());
+        let out_stream = irc_util::IrcResponseStream::new(&client, msg.response_target().unwrap(), flood);
 
         match app.get_matches_from_safe(args.iter().map(Cow::as_ref)) {
-            Ok(matches) => context.processor.process_matches(matches, out_stream),
+            Ok(matches) => {
+                match context.access.authorize(&msg_context, &matches) {
+                    AccessDecision::Allow => context.processor.process_matches(matches, out_stream),
+                    AccessDecision::Deny => {
+                        let _ = out_stream.send_message(context.access.refusal_message());
+                    }
+                }
+            }
             Err(e) => {
                 //TODO: Logging of some sort?
                 let _ = out_stream.send_message(&format!("Argument error: {:?}", e));
@@ -215,35 +649,37 @@ fn process_single_message<'a, CM, CP>(
 
 }
 
-fn process_message_streams<'a, CM, CP>(
+async fn process_message_streams<'a, CM, CP, AC, EH>(
     app: clap::App<'a, 'a>,
-    context: IrclapProcessor<CM, CP>,
-    client: ircp::IrcClient)
-    -> impl Future<Item=(), Error=irc::error::IrcError> + 'a
-    where CM: IrclapContextMapper + 'a,
-          CP: IrclapCommandProcessor + 'a {
-    client
-        .stream()
-        .filter(|m| {println!("{:?}", m); m.response_target().is_some()})
-        .for_each(move |msg| {
-            process_single_message(app.clone(), &context, &client, msg);
-            Ok(())
-        })
+    context: IrclapProcessor<CM, CP, AC, EH>,
+    client: Rc<ircp::Client>,
+    mut stream: ClientStream,
+    flood: Rc<RefCell<flood::FloodLimiter>>)
+    -> irc::error::Result<()>
+    where CM: IrclapContextMapper,
+          CP: IrclapCommandProcessor,
+          AC: IrclapAccessControl,
+          EH: IrclapEventHandler {
+    while let Some(msg) = stream.next().await.transpose()? {
+        if msg.response_target().is_some() || is_event_command(&msg.command) {
+            process_single_message(app.clone(), &context, &client, flood.clone(), msg);
+        }
+    }
+
+    Ok(())
 }
 
 /**
- * Create a new [Future] which will execute an `irclap` application.
+ * Connects, identifies, and processes messages for an `irclap` application
+ * until the connection ends.
  *
- * You will need the tokio reactor [Core][tokio_core::reactor::Core] to drive the resulting future. You
- * MUST have direct access to the Core. A [Handle], while sufficient to create the future,
- * is insufficient to run it. Handle requires a Future bounded with `'static`, which
- * this will almost certainly not provide. Future changes will probably make this more
- * embedded in the function signature.
+ * Await this from within a [tokio::task::LocalSet] (e.g. via
+ * `LocalSet::run_until`), since the flood limiter it spawns onto the
+ * current task set is built on `Rc`/`RefCell` rather than anything `Send`.
  *
- * For configuration, note that the irc config must have info needed to
- * identify with the IRC server, along with all the necessary options for
- * connecting in the first place. The future will identify with the server
- * using the nickname and identification setup from your config.
+ * `cfg` must have everything needed to identify with the IRC server, along
+ * with all the necessary options for connecting in the first place; the
+ * nickname and identification setup come from there.
  *
  * The [App][clap::App] has essentially no restrictions, but you should note that
  * the [NoBinaryName][clap::AppSettings::NoBinaryName] app setting will be added to the application
@@ -255,28 +691,280 @@ fn process_message_streams<'a, CM, CP>(
  *
  * The processor is the core part of your application, and is where all the
  * business logic or anything like that should happen.
+ *
+ * `trigger` configures how a message must be addressed before it's treated
+ * as a command at all; see [TriggerConfig] for the available options.
+ *
+ * `access` gates whether a successfully-parsed command is actually allowed
+ * to run; pass [AllowAll] if you don't need any restrictions, or see
+ * [HostmaskAccessControl] for a built-in hostmask-based implementation.
+ *
+ * `events` reacts to joins, parts, kicks, nick changes, and topic changes;
+ * pass [NoEventHandler] if you don't need any of those.
+ */
+pub async fn new_irclap_future<'a, CM, CP, AC, EH>(
+    cfg: ircp::Config,
+    trigger: TriggerConfig,
+    app: clap::App<'a, 'a>,
+    mapper: CM,
+    processor: CP,
+    access: AC,
+    events: EH)
+    -> irc::error::Result<()>
+    where CM: IrclapContextMapper,
+          CP: IrclapCommandProcessor,
+          AC: IrclapAccessControl,
+          EH: IrclapEventHandler {
+    let ctxt = IrclapProcessor::new(trigger, mapper, processor, access, events);
+
+    let mut client = ircp::Client::from_config(cfg).await?;
+    client.identify()?;
+    let stream = client.stream()?;
+
+    let client = Rc::new(client);
+    let limiter = Rc::new(RefCell::new(flood::FloodLimiter::new(flood::DEFAULT_FLOOD_CAPACITY)));
+    flood::spawn_refill_timer(client.clone(), limiter.clone(),
+                               Duration::from_secs(flood::DEFAULT_FLOOD_PERIOD_SECS));
+
+    let complete_app = app.setting(clap::AppSettings::NoBinaryName);
+
+    process_message_streams(complete_app, ctxt, client, stream, limiter).await
+}
+
+/**
+ * Identical to [new_irclap_future], but also wires up an
+ * [IrclapCtcpResponder] so standalone CTCP queries (`VERSION`, `PING`,
+ * `TIME`, `SOURCE`) are answered automatically instead of being passed
+ * to the clap `App`. Requires the `ctcp` feature.
  */
-pub fn new_irclap_future<'a, CM, CP>(
-    handle: Handle,
-    cfg: &'a ircp::Config,
+#[cfg(feature = "ctcp")]
+pub async fn new_irclap_future_with_ctcp<'a, CM, CP, AC, EH, CR>(
+    cfg: ircp::Config,
+    trigger: TriggerConfig,
     app: clap::App<'a, 'a>,
     mapper: CM,
-    processor: CP)
-    -> impl Future<Item=(), Error=irc::error::IrcError> + 'a
-    where CM: IrclapContextMapper + 'a,
-          CP: IrclapCommandProcessor + 'a{
-    let ctxt = IrclapProcessor::new(mapper, processor);
+    processor: CP,
+    access: AC,
+    events: EH,
+    ctcp_responder: CR)
+    -> irc::error::Result<()>
+    where CM: IrclapContextMapper,
+          CP: IrclapCommandProcessor,
+          AC: IrclapAccessControl,
+          EH: IrclapEventHandler,
+          CR: IrclapCtcpResponder + 'static {
+    let ctxt = IrclapProcessor::new(trigger, mapper, processor, access, events)
+        .with_ctcp_responder(Box::new(ctcp_responder));
 
-    //At least as of irc 0.13.4, this never fails
-    let irc_client_creator = ircp::IrcClient::new_future(handle, cfg).unwrap();
+    let mut client = ircp::Client::from_config(cfg).await?;
+    client.identify()?;
+    let stream = client.stream()?;
+
+    let client = Rc::new(client);
+    let limiter = Rc::new(RefCell::new(flood::FloodLimiter::new(flood::DEFAULT_FLOOD_CAPACITY)));
+    flood::spawn_refill_timer(client.clone(), limiter.clone(),
+                               Duration::from_secs(flood::DEFAULT_FLOOD_PERIOD_SECS));
 
     let complete_app = app.setting(clap::AppSettings::NoBinaryName);
 
-    irc_client_creator
-        //item 0 is the actual irc client
-        .and_then(|packed_client| packed_client.0.identify().map(|_| packed_client))
-        .and_then(move |PackedIrcClient(client, future)| {
-            //drive both sends (future) and processing (the process_message_streams result)
-            future.join(process_message_streams(complete_app, ctxt, client))
-        }).map(|_| ())
+    process_message_streams(complete_app, ctxt, client, stream, limiter).await
+}
+
+/**
+ * An [IrclapResponseStream] that writes each line to stdout via `println!`,
+ * for use with [run_cli].
+ */
+pub struct StdoutResponseStream;
+
+impl IrclapResponseStream for StdoutResponseStream {
+    fn send_message(&self, msg: &str) -> Result<(), irc::error::Error> {
+        println!("{}", msg);
+        Ok(())
+    }
+}
+
+/**
+ * What happened when [run_cli_with_args] handled a command; mostly
+ * useful so tests can assert on the outcome without scraping stdout.
+ */
+#[derive(Debug, PartialEq, Eq)]
+enum CliOutcome {
+    Ran,
+    Denied,
+    ArgError,
+}
+
+/**
+ * Runs an `irclap` application as a plain command-line tool, using the
+ * same `App`, mapper, and processor used for IRC. Arguments are read from
+ * [std::env::args], with the binary name stripped, and output goes to
+ * stdout via [StdoutResponseStream].
+ *
+ * Since there's no real `ircp::Message` in CLI mode, `channel` and
+ * `username` are supplied directly and fed to the mapper and access
+ * control as an [IrclapContext::Cli].
+ */
+pub fn run_cli<CM, CP, AC>(
+    app: clap::App,
+    mapper: CM,
+    processor: CP,
+    access: AC,
+    channel: Option<&str>,
+    username: Option<&str>)
+    where CM: IrclapContextMapper,
+          CP: IrclapCommandProcessor,
+          AC: IrclapAccessControl {
+    let raw_args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = raw_args.iter().map(String::as_str).collect();
+
+    run_cli_with_args(app, &mapper, &processor, &access, channel, username, args);
+}
+
+/**
+ * The actual logic behind [run_cli], taking `args` directly instead of
+ * reading [std::env::args] so it can be exercised from tests.
+ */
+fn run_cli_with_args<CM, CP, AC>(
+    app: clap::App,
+    mapper: &CM,
+    processor: &CP,
+    access: &AC,
+    channel: Option<&str>,
+    username: Option<&str>,
+    args: Vec<&str>)
+    -> CliOutcome
+    where CM: IrclapContextMapper,
+          CP: IrclapCommandProcessor,
+          AC: IrclapAccessControl {
+    let context = IrclapContext::Cli { channel: channel, username: username };
+    let args = mapper.prepare_command_args(args, &context);
+
+    let app = app.setting(clap::AppSettings::NoBinaryName);
+
+    match app.get_matches_from_safe(args.iter().map(Cow::as_ref)) {
+        Ok(matches) => {
+            match access.authorize(&context, &matches) {
+                AccessDecision::Allow => {
+                    processor.process_matches(matches, StdoutResponseStream);
+                    CliOutcome::Ran
+                }
+                AccessDecision::Deny => {
+                    println!("{}", access.refusal_message());
+                    CliOutcome::Denied
+                }
+            }
+        }
+        Err(e) => {
+            println!("Argument error: {:?}", e);
+            CliOutcome::ArgError
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use irc::client::prelude::{Message, Tag};
+
+    #[test]
+    fn looks_up_a_message_tag_by_key() {
+        let mut msg = Message::new(Some("nick!user@host"), "PRIVMSG", vec!["#chan"], Some("hi")).unwrap();
+        msg.tags = Some(vec![Tag("account".to_owned(), Some("alice".to_owned()))]);
+        let context = IrclapContext::Irc(&msg);
+
+        assert_eq!(context.tag("account"), Some("alice"));
+        assert_eq!(context.tag("missing"), None);
+    }
+
+    struct DenyAll;
+
+    impl IrclapAccessControl for DenyAll {
+        fn authorize(&self, _context: &IrclapContext, _matches: &clap::ArgMatches) -> AccessDecision {
+            AccessDecision::Deny
+        }
+    }
+
+    fn noop_processor<'a>(_matches: clap::ArgMatches<'a>, _resp: Box<dyn IrclapResponseStream + 'a>) {}
+
+    #[test]
+    fn runs_a_well_formed_command_when_allowed() {
+        let app = clap::App::new("test").arg(clap::Arg::with_name("thing"));
+        let mapper = IrclapSimpleContextMapping::none();
+
+        let outcome = run_cli_with_args(app, &mapper, &noop_processor, &AllowAll, None, None, vec!["hello"]);
+        assert_eq!(outcome, CliOutcome::Ran);
+    }
+
+    #[test]
+    fn reports_denied_when_access_control_refuses() {
+        let app = clap::App::new("test");
+        let mapper = IrclapSimpleContextMapping::none();
+
+        let outcome = run_cli_with_args(app, &mapper, &noop_processor, &DenyAll, None, None, vec![]);
+        assert_eq!(outcome, CliOutcome::Denied);
+    }
+
+    #[test]
+    fn reports_an_argument_error_for_unknown_flags() {
+        let app = clap::App::new("test");
+        let mapper = IrclapSimpleContextMapping::none();
+
+        let outcome = run_cli_with_args(app, &mapper, &noop_processor, &AllowAll, None, None, vec!["--nope"]);
+        assert_eq!(outcome, CliOutcome::ArgError);
+    }
+
+    #[test]
+    fn is_event_command_recognizes_join_part_kick_nick_topic_only() {
+        let msg = |cmd| Message::new(Some("usr"), cmd, vec!["#chan"], None).unwrap();
+
+        assert!(is_event_command(&msg("JOIN").command));
+        assert!(is_event_command(&Message::new(Some("usr"), "PART", vec!["#chan"], Some("bye")).unwrap().command));
+        assert!(is_event_command(&Message::new(Some("usr"), "KICK", vec!["#chan", "victim"], None).unwrap().command));
+        assert!(is_event_command(&Message::new(Some("usr"), "NICK", vec!["newnick"], None).unwrap().command));
+        assert!(is_event_command(&Message::new(Some("usr"), "TOPIC", vec!["#chan"], Some("new topic")).unwrap().command));
+
+        assert!(!is_event_command(&msg("PRIVMSG").command));
+    }
+
+    #[derive(Default)]
+    struct RecordingEvents {
+        self_kicked: RefCell<Option<(String, String)>>,
+        kicked_other: RefCell<Option<(String, String, String)>>,
+    }
+
+    impl IrclapEventHandler for RecordingEvents {
+        fn on_kick<RS: IrclapResponseStream>(&self, channel: &str, kicked: &str, by: &str, _reason: Option<&str>, _resp: RS) {
+            *self.kicked_other.borrow_mut() = Some((channel.to_owned(), kicked.to_owned(), by.to_owned()));
+        }
+
+        fn on_self_kicked<RS: IrclapResponseStream>(&self, channel: &str, by: &str, _reason: Option<&str>, _resp: RS) {
+            *self.self_kicked.borrow_mut() = Some((channel.to_owned(), by.to_owned()));
+        }
+    }
+
+    #[tokio::test]
+    async fn kicking_the_bot_itself_fires_on_self_kicked_not_on_kick() {
+        let cfg = ircp::Config {
+            nickname: Some("bot".to_owned()),
+            server: Some("irc.test.net".to_owned()),
+            use_mock_connection: true,
+            ..Default::default()
+        };
+        let client = ircp::Client::from_config(cfg).await.unwrap();
+        let flood = Rc::new(RefCell::new(flood::FloodLimiter::new(flood::DEFAULT_FLOOD_CAPACITY)));
+        let ctxt = IrclapProcessor::new(
+            TriggerConfig::nick_only(), IrclapSimpleContextMapping::none(),
+            noop_processor, AllowAll, RecordingEvents::default());
+
+        let self_kick = Message::new(Some("admin!user@host"), "KICK", vec!["#chan", "bot"], Some("bye")).unwrap();
+        process_single_message(clap::App::new("test"), &ctxt, &client, flood.clone(), self_kick);
+
+        assert_eq!(*ctxt.events.self_kicked.borrow(), Some(("#chan".to_owned(), "admin".to_owned())));
+        assert_eq!(*ctxt.events.kicked_other.borrow(), None);
+
+        let other_kick = Message::new(Some("admin!user@host"), "KICK", vec!["#chan", "someoneelse"], Some("bye")).unwrap();
+        process_single_message(clap::App::new("test"), &ctxt, &client, flood.clone(), other_kick);
+
+        assert_eq!(*ctxt.events.kicked_other.borrow(), Some(("#chan".to_owned(), "someoneelse".to_owned(), "admin".to_owned())));
+    }
 }